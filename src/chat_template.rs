@@ -0,0 +1,192 @@
+//! Builds CodeGeeX4's ChatGLM4 conversation format instead of feeding the raw prompt straight
+//! into the tokenizer, and parses tool-call segments back out of what the model generates.
+use serde::Deserialize;
+
+/// One function the model is allowed to call, as passed via `--tools`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ToolDeclaration {
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub parameters: serde_json::Value,
+}
+
+/// A structured function call recovered from the assistant's raw output. The plain text that
+/// came before it is kept in [`AssistantReply::text`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+/// A parsed turn of assistant output: free text and, if the model invoked one, a tool call.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AssistantReply {
+    pub text: String,
+    pub tool_call: Option<ToolCall>,
+}
+
+/// Renders a ChatGLM4 prompt: `[gMASK]<sop>` followed by an optional `<|system|>` turn (with an
+/// injected function-declaration block when tools are supplied), the `<|user|>` turn, and a
+/// trailing `<|assistant|>` tag for the model to continue from.
+pub fn build_prompt(system: Option<&str>, tools: &[ToolDeclaration], user: &str) -> String {
+    let mut prompt = String::from("[gMASK]<sop>");
+    if let Some(system) = system {
+        prompt.push_str("<|system|>\n");
+        prompt.push_str(system);
+        if !tools.is_empty() {
+            prompt.push('\n');
+            prompt.push_str(&render_tools(tools));
+        }
+    } else if !tools.is_empty() {
+        prompt.push_str("<|system|>\n");
+        prompt.push_str(&render_tools(tools));
+    }
+    prompt.push_str("<|user|>\n");
+    prompt.push_str(user);
+    prompt.push_str("<|assistant|>\n");
+    prompt
+}
+
+fn render_tools(tools: &[ToolDeclaration]) -> String {
+    let mut out = String::from("你可以使用以下工具：\n");
+    for tool in tools {
+        out.push_str(&format!(
+            "{}: {}\n参数: {}\n",
+            tool.name,
+            tool.description,
+            tool.parameters
+        ));
+    }
+    out
+}
+
+/// Splits a raw assistant turn into leading text and an optional tool call. CodeGeeX4 emits a
+/// tool call as a line holding the function name followed by a line of JSON arguments, e.g.
+/// `get_weather\n{"city": "Beijing"}`.
+pub fn parse_assistant_reply(raw: &str) -> AssistantReply {
+    let raw = raw.trim_start_matches("<|assistant|>").trim();
+    for (idx, line) in raw.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let rest: String = raw
+            .lines()
+            .skip(idx + 1)
+            .collect::<Vec<_>>()
+            .join("\n")
+            .trim()
+            .to_string();
+        if !rest.starts_with('{') {
+            continue;
+        }
+        if line.chars().all(|c| c.is_alphanumeric() || c == '_') && serde_json::from_str::<serde_json::Value>(&rest).is_ok() {
+            let text = raw.lines().take(idx).collect::<Vec<_>>().join("\n");
+            return AssistantReply {
+                text,
+                tool_call: Some(ToolCall {
+                    name: line.to_string(),
+                    arguments: rest,
+                }),
+            };
+        }
+        break;
+    }
+    AssistantReply {
+        text: raw.to_string(),
+        tool_call: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weather_tool() -> ToolDeclaration {
+        ToolDeclaration {
+            name: "get_weather".to_string(),
+            description: "Gets the weather for a city.".to_string(),
+            parameters: serde_json::json!({"city": "string"}),
+        }
+    }
+
+    #[test]
+    fn build_prompt_with_neither_system_nor_tools() {
+        let prompt = build_prompt(None, &[], "hi");
+        assert_eq!(prompt, "[gMASK]<sop><|user|>\nhi<|assistant|>\n");
+    }
+
+    #[test]
+    fn build_prompt_with_system_only() {
+        let prompt = build_prompt(Some("Be concise."), &[], "hi");
+        assert_eq!(
+            prompt,
+            "[gMASK]<sop><|system|>\nBe concise.<|user|>\nhi<|assistant|>\n"
+        );
+    }
+
+    #[test]
+    fn build_prompt_with_tools_only_still_emits_a_system_turn() {
+        let tools = [weather_tool()];
+        let prompt = build_prompt(None, &tools, "hi");
+        assert!(prompt.starts_with("[gMASK]<sop><|system|>\n"));
+        assert!(prompt.contains(&render_tools(&tools)));
+        assert!(prompt.ends_with("<|user|>\nhi<|assistant|>\n"));
+    }
+
+    #[test]
+    fn build_prompt_with_system_and_tools() {
+        let tools = [weather_tool()];
+        let prompt = build_prompt(Some("Be concise."), &tools, "hi");
+        assert_eq!(
+            prompt,
+            format!(
+                "[gMASK]<sop><|system|>\nBe concise.\n{}<|user|>\nhi<|assistant|>\n",
+                render_tools(&tools)
+            )
+        );
+    }
+
+    #[test]
+    fn plain_text_reply_has_no_tool_call() {
+        let reply = parse_assistant_reply("Sure, here is the answer you asked for.");
+        assert_eq!(
+            reply,
+            AssistantReply {
+                text: "Sure, here is the answer you asked for.".to_string(),
+                tool_call: None,
+            }
+        );
+    }
+
+    #[test]
+    fn tool_call_is_split_from_leading_text() {
+        let reply = parse_assistant_reply(
+            "Let me check that for you.\nget_weather\n{\"city\": \"Beijing\"}",
+        );
+        assert_eq!(
+            reply,
+            AssistantReply {
+                text: "Let me check that for you.".to_string(),
+                tool_call: Some(ToolCall {
+                    name: "get_weather".to_string(),
+                    arguments: "{\"city\": \"Beijing\"}".to_string(),
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn function_like_line_with_invalid_json_body_is_not_a_tool_call() {
+        let raw = "get_weather\nthis is not json";
+        let reply = parse_assistant_reply(raw);
+        assert_eq!(
+            reply,
+            AssistantReply {
+                text: raw.to_string(),
+                tool_call: None,
+            }
+        );
+    }
+}
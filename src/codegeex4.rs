@@ -0,0 +1,433 @@
+use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
+use candle_nn::{Embedding, Linear, Module, VarBuilder};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub num_layers: usize,
+    pub padded_vocab_size: usize,
+    pub hidden_size: usize,
+    pub ffn_hidden_size: usize,
+    pub kv_channels: usize,
+    pub num_attention_heads: usize,
+    pub seq_length: usize,
+    pub layernorm_epsilon: f64,
+    pub rmsnorm: bool,
+    pub apply_residual_connection_post_layernorm: bool,
+    pub post_layer_norm: bool,
+    pub add_bias_linear: bool,
+    pub add_qkv_bias: bool,
+    pub multi_query_attention: bool,
+    pub multi_query_group_num: usize,
+    pub attention_softmax_in_fp32: bool,
+    pub fp32_residual_connection: bool,
+}
+
+impl Config {
+    pub fn codegeex4() -> Self {
+        Self {
+            num_layers: 40,
+            padded_vocab_size: 151552,
+            hidden_size: 4096,
+            ffn_hidden_size: 13696,
+            kv_channels: 128,
+            num_attention_heads: 32,
+            seq_length: 8192,
+            layernorm_epsilon: 1e-5,
+            rmsnorm: true,
+            apply_residual_connection_post_layernorm: false,
+            post_layer_norm: true,
+            add_bias_linear: false,
+            add_qkv_bias: true,
+            multi_query_attention: true,
+            multi_query_group_num: 2,
+            attention_softmax_in_fp32: true,
+            fp32_residual_connection: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RmsNorm {
+    weight: Tensor,
+    eps: f64,
+}
+
+impl RmsNorm {
+    fn new(size: usize, eps: f64, vb: VarBuilder) -> Result<Self> {
+        let weight = vb.get(size, "weight")?;
+        Ok(Self { weight, eps })
+    }
+}
+
+impl Module for RmsNorm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        candle_nn::ops::rms_norm(xs, &self.weight, self.eps as f32)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    cache: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(cfg: &Config, dtype: DType, dev: &Device) -> Result<Self> {
+        let rotary_dim = cfg.kv_channels / 2;
+        let theta: Vec<_> = (0..rotary_dim)
+            .step_by(2)
+            .map(|i| 1f32 / 10000f64.powf(i as f64 / rotary_dim as f64) as f32)
+            .collect();
+        let theta = Tensor::new(theta.as_slice(), dev)?;
+        let idx_theta = Tensor::arange(0, cfg.seq_length as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((cfg.seq_length, 1))?
+            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+        let cache = Tensor::stack(&[idx_theta.cos()?, idx_theta.sin()?], D::Minus1)?;
+        let cache = cache.to_dtype(dtype)?;
+        Ok(Self { cache })
+    }
+
+    fn apply(&self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (b_sz, num_heads, seq_len, n_embd) = xs.dims4()?;
+        let half = n_embd / 2;
+        let xs_rot = xs.narrow(D::Minus1, 0, half)?;
+        let xs_pass = xs.narrow(D::Minus1, half, half)?;
+        let xs_rot = xs_rot.reshape((b_sz, num_heads, seq_len, half / 2, 2))?;
+        let cache = self.cache.narrow(0, index_pos, seq_len)?;
+        let cos = cache.narrow(D::Minus1, 0, 1)?.broadcast_as(xs_rot.shape())?;
+        let sin = cache.narrow(D::Minus1, 1, 1)?.broadcast_as(xs_rot.shape())?;
+        let x0 = xs_rot.narrow(D::Minus1, 0, 1)?;
+        let x1 = xs_rot.narrow(D::Minus1, 1, 1)?;
+        let rotated = Tensor::cat(
+            &[
+                (&x0 * &cos)?.broadcast_sub(&(&x1 * &sin)?)?,
+                (&x0 * &sin)?.broadcast_add(&(&x1 * &cos)?)?,
+            ],
+            D::Minus1,
+        )?
+        .flatten_from(D::Minus2)?;
+        Tensor::cat(&[rotated, xs_pass], D::Minus1)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    dense_h_to_4h: Linear,
+    dense_4h_to_h: Linear,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dense_h_to_4h = candle_nn::linear(
+            cfg.hidden_size,
+            cfg.ffn_hidden_size * 2,
+            vb.pp("dense_h_to_4h"),
+        )?;
+        let dense_4h_to_h = candle_nn::linear(
+            cfg.ffn_hidden_size,
+            cfg.hidden_size,
+            vb.pp("dense_4h_to_h"),
+        )?;
+        Ok(Self {
+            dense_h_to_4h,
+            dense_4h_to_h,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let up_states = self.dense_h_to_4h.forward(xs)?;
+        let gate = up_states.chunk(2, D::Minus1)?;
+        let xs = (gate[0].silu()? * &gate[1])?;
+        self.dense_4h_to_h.forward(&xs)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SelfAttention {
+    query_key_value: Linear,
+    dense: Linear,
+    num_attention_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    rotary_embedding: RotaryEmbedding,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl SelfAttention {
+    fn new(cfg: &Config, dtype: DType, vb: VarBuilder) -> Result<Self> {
+        let head_dim = cfg.kv_channels;
+        let num_kv_heads = if cfg.multi_query_attention {
+            cfg.multi_query_group_num
+        } else {
+            cfg.num_attention_heads
+        };
+        let qkv_size = cfg.hidden_size + 2 * num_kv_heads * head_dim;
+        let query_key_value = if cfg.add_qkv_bias {
+            candle_nn::linear(cfg.hidden_size, qkv_size, vb.pp("query_key_value"))?
+        } else {
+            candle_nn::linear_no_bias(cfg.hidden_size, qkv_size, vb.pp("query_key_value"))?
+        };
+        let dense = if cfg.add_bias_linear {
+            candle_nn::linear(cfg.hidden_size, cfg.hidden_size, vb.pp("dense"))?
+        } else {
+            candle_nn::linear_no_bias(cfg.hidden_size, cfg.hidden_size, vb.pp("dense"))?
+        };
+        let rotary_embedding = RotaryEmbedding::new(cfg, dtype, vb.device())?;
+        Ok(Self {
+            query_key_value,
+            dense,
+            num_attention_heads: cfg.num_attention_heads,
+            num_kv_heads,
+            head_dim,
+            rotary_embedding,
+            kv_cache: None,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+        let qkv = self.query_key_value.forward(xs)?;
+        let q_size = self.num_attention_heads * self.head_dim;
+        let kv_size = self.num_kv_heads * self.head_dim;
+        let q = qkv.narrow(D::Minus1, 0, q_size)?;
+        let k = qkv.narrow(D::Minus1, q_size, kv_size)?;
+        let v = qkv.narrow(D::Minus1, q_size + kv_size, kv_size)?;
+
+        let q = q
+            .reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let q = self.rotary_embedding.apply(&q, index_pos)?.contiguous()?;
+        let k = self.rotary_embedding.apply(&k, index_pos)?.contiguous()?;
+
+        let (k, v) = match &self.kv_cache {
+            None => (k, v),
+            Some((prev_k, prev_v)) => {
+                let k = Tensor::cat(&[prev_k, &k], 2)?;
+                let v = Tensor::cat(&[prev_v, &v], 2)?;
+                (k, v)
+            }
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let repeat = self.num_attention_heads / self.num_kv_heads;
+        let k = candle_transformers::utils::repeat_kv(k, repeat)?;
+        let v = candle_transformers::utils::repeat_kv(v, repeat)?;
+
+        let scale = 1f64 / (self.head_dim as f64).sqrt();
+        let attn_weights = (q.matmul(&k.transpose(D::Minus2, D::Minus1)?)? * scale)?;
+        let attn_weights = if seq_len <= 1 {
+            attn_weights
+        } else {
+            let mask = Tensor::triu2(seq_len, DType::U8, xs.device())?.broadcast_as((
+                b_sz,
+                self.num_attention_heads,
+                seq_len,
+                seq_len,
+            ))?;
+            let neg_inf = Tensor::new(f32::NEG_INFINITY, xs.device())?.broadcast_as(mask.shape())?;
+            mask.where_cond(&neg_inf.to_dtype(attn_weights.dtype())?, &attn_weights)?
+        };
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        let attn_output = attn_weights.matmul(&v)?;
+        let attn_output = attn_output
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, self.num_attention_heads * self.head_dim))?;
+        self.dense.forward(&attn_output)
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.kv_cache = None
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    input_layernorm: RmsNorm,
+    self_attention: SelfAttention,
+    post_attention_layernorm: RmsNorm,
+    mlp: Mlp,
+    apply_residual_connection_post_layernorm: bool,
+}
+
+impl Block {
+    fn new(cfg: &Config, dtype: DType, vb: VarBuilder) -> Result<Self> {
+        let input_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.layernorm_epsilon,
+            vb.pp("input_layernorm"),
+        )?;
+        let self_attention = SelfAttention::new(cfg, dtype, vb.pp("self_attention"))?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.layernorm_epsilon,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        let mlp = Mlp::new(cfg, vb.pp("mlp"))?;
+        Ok(Self {
+            input_layernorm,
+            self_attention,
+            post_attention_layernorm,
+            mlp,
+            apply_residual_connection_post_layernorm: cfg
+                .apply_residual_connection_post_layernorm,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let residual = if self.apply_residual_connection_post_layernorm {
+            xs.clone()
+        } else {
+            xs.clone()
+        };
+        let layernorm_output = self.input_layernorm.forward(xs)?;
+        let attention_output = self.self_attention.forward(&layernorm_output, index_pos)?;
+        let residual = if self.apply_residual_connection_post_layernorm {
+            layernorm_output.clone()
+        } else {
+            residual
+        };
+        let layernorm_input = (residual + attention_output)?;
+        let layernorm_output = self.post_attention_layernorm.forward(&layernorm_input)?;
+        let mlp_output = self.mlp.forward(&layernorm_output)?;
+        let residual = if self.apply_residual_connection_post_layernorm {
+            layernorm_output
+        } else {
+            layernorm_input.clone()
+        };
+        residual + mlp_output
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.self_attention.reset_kv_cache()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Transformer {
+    layers: Vec<Block>,
+    final_layernorm: Option<RmsNorm>,
+}
+
+impl Transformer {
+    fn new(cfg: &Config, dtype: DType, vb: VarBuilder) -> Result<Self> {
+        let vb_l = vb.pp("layers");
+        let mut layers = Vec::with_capacity(cfg.num_layers);
+        for layer_idx in 0..cfg.num_layers {
+            let block = Block::new(cfg, dtype, vb_l.pp(layer_idx))?;
+            layers.push(block)
+        }
+        let final_layernorm = if cfg.post_layer_norm {
+            let ln = RmsNorm::new(
+                cfg.hidden_size,
+                cfg.layernorm_epsilon,
+                vb.pp("final_layernorm"),
+            )?;
+            Some(ln)
+        } else {
+            None
+        };
+        Ok(Self {
+            layers,
+            final_layernorm,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, index_pos)?;
+        }
+        match self.final_layernorm.as_ref() {
+            Some(ln) => xs.apply(ln),
+            None => Ok(xs),
+        }
+    }
+
+    fn reset_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.reset_kv_cache()
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct WordEmbedding {
+    word_embeddings: Embedding,
+    fp32_residual_connection: bool,
+}
+
+impl WordEmbedding {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let word_embeddings = candle_nn::embedding(
+            cfg.padded_vocab_size,
+            cfg.hidden_size,
+            vb.pp("word_embeddings"),
+        )?;
+        Ok(Self {
+            word_embeddings,
+            fp32_residual_connection: cfg.fp32_residual_connection,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.word_embeddings.forward(xs)?;
+        if self.fp32_residual_connection {
+            xs.to_dtype(DType::F32)
+        } else {
+            xs.contiguous()
+        }
+    }
+}
+
+/// The ChatGLM4-based CodeGeeX4 model, loaded from full-precision safetensors weights.
+#[derive(Debug, Clone)]
+pub struct Model {
+    embedding: WordEmbedding,
+    transformer: Transformer,
+    output_layer: Linear,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb = vb.pp("transformer");
+        let embedding = WordEmbedding::new(cfg, vb.pp("embedding"))?;
+        let transformer = Transformer::new(cfg, vb.dtype(), vb.pp("encoder"))?;
+        let output_layer = candle_nn::linear_no_bias(
+            cfg.hidden_size,
+            cfg.padded_vocab_size,
+            vb.pp("output_layer"),
+        )?;
+        Ok(Self {
+            embedding,
+            transformer,
+            output_layer,
+        })
+    }
+
+    pub fn forward(&mut self, xs: &Tensor) -> Result<Tensor> {
+        let (_b_size, seq_len) = xs.dims2()?;
+        let index_pos = self.transformer.layers[0]
+            .self_attention
+            .kv_cache
+            .as_ref()
+            .map_or(0, |(k, _)| k.dim(2).unwrap_or(0));
+        let xs = self.embedding.forward(xs)?;
+        let xs = self.transformer.forward(&xs, index_pos)?;
+        let xs = xs.i((.., seq_len - 1, ..))?;
+        self.output_layer.forward(&xs)
+    }
+
+    pub fn reset_kv_cache(&mut self) {
+        self.transformer.reset_kv_cache()
+    }
+}
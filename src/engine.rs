@@ -0,0 +1,331 @@
+//! Library-level inference loop, separated from the CLI's stdin/stdout handling so the crate
+//! can be embedded behind a server or bot: load the model once with [`Engine::load`], then call
+//! [`Engine::generate`] for each incoming prompt.
+use candle_core::{DType, Device, Result, Tensor};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use tokenizers::Tokenizer;
+
+use crate::chat_template::{self, AssistantReply, ToolDeclaration};
+use crate::codegeex4;
+use crate::quantized_codegeex4;
+use crate::token_output_stream::TokenOutputStream;
+
+/// Either the full-precision safetensors model or its GGUF-quantized counterpart.
+pub enum Model {
+    Float(codegeex4::Model),
+    Quantized(quantized_codegeex4::ModelWeights),
+}
+
+impl Model {
+    fn forward(&mut self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Float(m) => m.forward(xs),
+            Self::Quantized(m) => m.forward(xs),
+        }
+    }
+
+    fn reset_kv_cache(&mut self) {
+        match self {
+            Self::Float(m) => m.reset_kv_cache(),
+            Self::Quantized(m) => m.reset_kv_cache(),
+        }
+    }
+}
+
+/// Sampling knobs passed to [`Engine::load`]. The engine builds its logits processor from them
+/// once, at load time; they cannot be changed afterwards without building a new [`Engine`].
+#[derive(Debug, Clone)]
+pub struct SamplingOptions {
+    pub seed: u64,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub greedy: bool,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+}
+
+fn logits_processor(sampling: &SamplingOptions) -> LogitsProcessor {
+    let temperature = sampling.temperature.filter(|t| *t > 0.);
+    let mode = if sampling.greedy || temperature.is_none() {
+        Sampling::ArgMax
+    } else {
+        let temperature = temperature.unwrap();
+        match (sampling.top_k, sampling.top_p) {
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (None, None) => Sampling::All { temperature },
+        }
+    };
+    LogitsProcessor::from_sampling(sampling.seed, mode)
+}
+
+/// Zeroes out (sets to `-inf`) every logit whose probability is below `min_p` of the most
+/// likely token's probability, as candle's `Sampling` enum has no native min-p variant.
+fn apply_min_p(logits: &Tensor, min_p: f64) -> Result<Tensor> {
+    let probs = candle_nn::ops::softmax_last_dim(&logits.to_dtype(DType::F32)?)?;
+    let probs_v: Vec<f32> = probs.to_vec1()?;
+    let max_prob = probs_v.iter().cloned().fold(f32::MIN, f32::max);
+    let threshold = max_prob * min_p as f32;
+    let logits_v: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+    let filtered: Vec<f32> = logits_v
+        .iter()
+        .zip(probs_v.iter())
+        .map(|(&logit, &prob)| if prob < threshold { f32::NEG_INFINITY } else { logit })
+        .collect();
+    Tensor::new(filtered.as_slice(), logits.device())?.to_dtype(logits.dtype())
+}
+
+/// Owns a loaded model and its tokenizer so generation can be repeated across many prompts
+/// without paying the load cost again. `main.rs` is a thin wrapper around this.
+pub struct Engine {
+    model: Model,
+    device: Device,
+    dtype: DType,
+    tokenizer: TokenOutputStream,
+    logits_processor: LogitsProcessor,
+    sampling: SamplingOptions,
+    system: Option<String>,
+    tools: Vec<ToolDeclaration>,
+}
+
+impl Engine {
+    pub fn load(
+        model: Model,
+        tokenizer: Tokenizer,
+        device: Device,
+        dtype: DType,
+        sampling: SamplingOptions,
+    ) -> Self {
+        let logits_processor = logits_processor(&sampling);
+        Self {
+            model,
+            device,
+            dtype,
+            tokenizer: TokenOutputStream::new(tokenizer),
+            logits_processor,
+            sampling,
+            system: None,
+            tools: Vec::new(),
+        }
+    }
+
+    /// Sets the system prompt and tool declarations used to build the ChatGLM4 chat template
+    /// for subsequent [`Engine::generate`] calls.
+    pub fn with_chat_template(mut self, system: Option<String>, tools: Vec<ToolDeclaration>) -> Self {
+        self.system = system;
+        self.tools = tools;
+        self
+    }
+
+    /// Tokenizes the rendered ChatGLM4 prompt and returns its `(id, piece)` pairs, for callers
+    /// that want to display what the model actually sees (e.g. `--verbose-prompt`).
+    pub fn tokenize_prompt(&self, prompt: &str) -> Result<Vec<(u32, String)>> {
+        let rendered = chat_template::build_prompt(self.system.as_deref(), &self.tools, prompt);
+        let encoding = self
+            .tokenizer
+            .tokenizer()
+            .encode(rendered, true)
+            .map_err(candle_core::Error::msg)?;
+        Ok(encoding
+            .get_ids()
+            .iter()
+            .zip(encoding.get_tokens().iter())
+            .map(|(id, token)| (*id, token.replace('▁', " ").replace("<0x0A>", "\n")))
+            .collect())
+    }
+
+    /// Generates a reply to `prompt`, calling `on_token` with each newly-decoded chunk of text
+    /// as it becomes available. `on_token` returns `Ok(false)` to stop generation early.
+    ///
+    /// `on_debug`, when given, is called with `(index, raw_token_id, decoded_token)` for every
+    /// token as it is sampled, before it goes through the UTF-8-safe buffering `on_token` sees
+    /// (e.g. to power `--verbose-prompt`'s raw per-token dump).
+    pub fn generate(
+        &mut self,
+        prompt: &str,
+        sample_len: usize,
+        mut on_token: impl FnMut(&str) -> Result<bool>,
+        mut on_debug: Option<&mut dyn FnMut(usize, u32, &str) -> Result<()>>,
+    ) -> Result<AssistantReply> {
+        self.tokenizer.clear();
+        self.model.reset_kv_cache();
+
+        let rendered = chat_template::build_prompt(self.system.as_deref(), &self.tools, prompt);
+        let encoding = self
+            .tokenizer
+            .tokenizer()
+            .encode(rendered, true)
+            .map_err(candle_core::Error::msg)?;
+        if encoding.is_empty() {
+            candle_core::bail!("empty prompts are not supported in the chatglm model");
+        }
+        let eos_token = self
+            .tokenizer
+            .get_token("<|endoftext|>")
+            .ok_or_else(|| candle_core::Error::Msg("cannot find the endoftext token".into()))?;
+
+        let mut tokens = encoding.get_ids().to_vec();
+        let mut generated = Vec::new();
+        for index in 0..sample_len {
+            let context_size = if index > 0 { 1 } else { tokens.len() };
+            let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
+            let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
+            let logits = self.model.forward(&input)?;
+            let logits = logits.squeeze(0)?.to_dtype(self.dtype)?;
+            let logits = if self.sampling.repeat_penalty == 1. {
+                logits
+            } else {
+                let start_at = tokens.len().saturating_sub(self.sampling.repeat_last_n);
+                candle_transformers::utils::apply_repeat_penalty(
+                    &logits,
+                    self.sampling.repeat_penalty,
+                    &tokens[start_at..],
+                )?
+            };
+            let logits = match self.sampling.min_p {
+                Some(min_p) if !self.sampling.greedy => apply_min_p(&logits, min_p)?,
+                _ => logits,
+            };
+
+            let next_token = self.logits_processor.sample(&logits)?;
+            tokens.push(next_token);
+            if let Some(on_debug) = on_debug.as_mut() {
+                let decoded = self
+                    .tokenizer
+                    .tokenizer()
+                    .decode(&[next_token], true)
+                    .map_err(candle_core::Error::msg)?;
+                on_debug(index, next_token, &decoded)?;
+            }
+            if next_token == eos_token {
+                break;
+            }
+            if let Some(chunk) = self.tokenizer.next_token(next_token)? {
+                let keep_going = on_token(&chunk)?;
+                generated.push(chunk);
+                if !keep_going {
+                    break;
+                }
+            }
+        }
+        if let Some(rest) = self.tokenizer.decode_rest()? {
+            on_token(&rest)?;
+            generated.push(rest);
+        }
+        Ok(chat_template::parse_assistant_reply(&generated.concat()))
+    }
+
+    pub fn reset_kv_cache(&mut self) {
+        self.model.reset_kv_cache()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base_sampling() -> SamplingOptions {
+        SamplingOptions {
+            seed: 0,
+            temperature: Some(1.0),
+            top_p: None,
+            top_k: None,
+            min_p: None,
+            greedy: false,
+            repeat_penalty: 1.0,
+            repeat_last_n: 64,
+        }
+    }
+
+    fn logits(values: &[f32]) -> Tensor {
+        Tensor::new(values, &Device::Cpu).unwrap()
+    }
+
+    #[test]
+    fn greedy_always_picks_the_max_logit_regardless_of_seed() {
+        for seed in [0, 1, 42] {
+            let sampling = SamplingOptions {
+                seed,
+                greedy: true,
+                ..base_sampling()
+            };
+            let mut processor = logits_processor(&sampling);
+            let token = processor.sample(&logits(&[0.1, 0.2, 9.0, 0.3])).unwrap();
+            assert_eq!(token, 2);
+        }
+    }
+
+    #[test]
+    fn temperature_absent_falls_back_to_argmax_even_when_not_greedy() {
+        let sampling = SamplingOptions {
+            temperature: None,
+            ..base_sampling()
+        };
+        let mut processor = logits_processor(&sampling);
+        let token = processor.sample(&logits(&[5.0, 0.1, 0.2])).unwrap();
+        assert_eq!(token, 0);
+    }
+
+    #[test]
+    fn top_k_one_is_deterministic_regardless_of_temperature() {
+        let sampling = SamplingOptions {
+            temperature: Some(10.0),
+            top_k: Some(1),
+            ..base_sampling()
+        };
+        let mut processor = logits_processor(&sampling);
+        let token = processor.sample(&logits(&[0.1, 8.0, 0.2])).unwrap();
+        assert_eq!(token, 1);
+    }
+
+    #[test]
+    fn top_p_near_zero_collapses_to_the_top_token() {
+        let sampling = SamplingOptions {
+            top_p: Some(1e-6),
+            ..base_sampling()
+        };
+        let mut processor = logits_processor(&sampling);
+        let token = processor.sample(&logits(&[0.1, 0.2, 7.0])).unwrap();
+        assert_eq!(token, 2);
+    }
+
+    #[test]
+    fn top_k_then_top_p_with_k_one_is_deterministic() {
+        let sampling = SamplingOptions {
+            top_p: Some(0.9),
+            top_k: Some(1),
+            ..base_sampling()
+        };
+        let mut processor = logits_processor(&sampling);
+        let token = processor.sample(&logits(&[0.3, 6.0, 0.1])).unwrap();
+        assert_eq!(token, 1);
+    }
+
+    #[test]
+    fn neither_top_k_nor_top_p_samples_from_the_full_distribution() {
+        // Near-uniform logits: across a handful of seeds the unfiltered distribution should
+        // produce more than one distinct token, unlike the deterministic modes above.
+        let mut seen = std::collections::HashSet::new();
+        for seed in 0..20 {
+            let sampling = SamplingOptions {
+                seed,
+                ..base_sampling()
+            };
+            let mut processor = logits_processor(&sampling);
+            seen.insert(processor.sample(&logits(&[1.0, 1.0, 1.0, 1.0])).unwrap());
+        }
+        assert!(seen.len() > 1);
+    }
+
+    #[test]
+    fn apply_min_p_masks_logits_below_relative_threshold() {
+        let filtered = apply_min_p(&logits(&[5.0, 4.0, -1.0]), 0.5).unwrap();
+        let values: Vec<f32> = filtered.to_vec1().unwrap();
+        assert_eq!(values[0], 5.0);
+        assert_eq!(values[1], f32::NEG_INFINITY);
+        assert_eq!(values[2], f32::NEG_INFINITY);
+    }
+}
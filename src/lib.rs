@@ -0,0 +1,6 @@
+pub mod chat_template;
+pub mod codegeex4;
+pub mod engine;
+pub mod quantized_codegeex4;
+pub mod token_output_stream;
+pub mod worker;
@@ -5,144 +5,147 @@ extern crate intel_mkl_src;
 extern crate accelerate_src;
 
 use clap::Parser;
-use codegeex4_candle::codegeex4::*;
+use codegeex4_candle::chat_template::ToolDeclaration;
+use codegeex4_candle::codegeex4;
+use codegeex4_candle::engine::{Engine, Model, SamplingOptions};
+use codegeex4_candle::quantized_codegeex4;
+use codegeex4_candle::worker::{GenerationEvent, WorkerHandle};
 use owo_colors::{self, OwoColorize};
+use serde::Deserialize;
 use std::io::BufRead;
 use std::io::BufReader;
 
 use candle_core as candle;
-use candle_core::{DType, Device, Tensor};
+use candle_core::quantized::gguf_file;
+use candle_core::DType;
 use candle_nn::VarBuilder;
-use candle_transformers::generation::LogitsProcessor;
 use hf_hub::{Repo, RepoType};
 use rand::Rng;
 use tokenizers::Tokenizer;
 
-struct TextGeneration {
-    model: Model,
-    device: Device,
-    tokenizer: Tokenizer,
-    logits_processor: LogitsProcessor,
-    repeat_penalty: f32,
-    repeat_last_n: usize,
-    verbose_prompt: bool,
-    dtype: DType,
-}
+/// Reads prompts from stdin and prints each generated chunk as it streams out of the engine.
+/// When `verbose_prompt` is set, also dumps the tokenized prompt and each raw/decoded token pair
+/// as generation proceeds.
+fn run(engine: &mut Engine, sample_len: usize, verbose_prompt: bool) -> candle::Result<()> {
+    use std::io::Write;
 
-impl TextGeneration {
-    #[allow(clippy::too_many_arguments)]
-    fn new(
-        model: Model,
-        tokenizer: Tokenizer,
-        seed: u64,
-        temp: Option<f64>,
-        top_p: Option<f64>,
-        repeat_penalty: f32,
-        repeat_last_n: usize,
-        verbose_prompt: bool,
-        device: &Device,
-        dtype: DType,
-    ) -> Self {
-        let logits_processor = LogitsProcessor::new(seed, temp, top_p);
-        Self {
-            model,
-            tokenizer,
-            logits_processor,
-            repeat_penalty,
-            repeat_last_n,
-            verbose_prompt,
-            device: device.clone(),
-            dtype,
-        }
-    }
+    let stdin = std::io::stdin();
+    let reader = BufReader::new(stdin);
+    // 从标准输入读取prompt
+    for line in reader.lines() {
+        println!("[欢迎使用Codegeex4,请输入prompt]");
+        let line = line.expect("Failed to read line");
 
-    fn run(&mut self, sample_len: usize) -> Result<(), ()> {
-        use std::io::Write;
-
-        let stdin = std::io::stdin();
-        let reader = BufReader::new(stdin);
-        // 从标准输入读取prompt
-        for line in reader.lines() {
-            println!("[欢迎使用Codegeex4,请输入prompt]");
-            let line = line.expect("Failed to read line");
-            let tokens = self.tokenizer.encode(line, true).expect("tokens error");
-            if tokens.is_empty() {
-                panic!("Empty prompts are not supported in the chatglm model.")
+        if verbose_prompt {
+            for (id, token) in engine.tokenize_prompt(&line)? {
+                println!("{id:7} -> {token}");
             }
-            if self.verbose_prompt {
-                for (token, id) in tokens.get_tokens().iter().zip(tokens.get_ids().iter()) {
-                    let token = token.replace('▁', " ").replace("<0x0A>", "\n");
-                    println!("{id:7} -> '{token}'");
-                }
-            }
-            let eos_token = match self.tokenizer.get_vocab(true).get("<|endoftext|>") {
-                Some(token) => *token,
-                None => panic!("cannot find the endoftext token"),
-            };
-            let mut tokens = tokens.get_ids().to_vec();
-            let mut generated_tokens = 0usize;
-
-            std::io::stdout().flush().expect("output flush error");
-            let start_gen = std::time::Instant::now();
-
-            //            println!("\n 开始生成");
-            println!("samplelen {}", sample_len.blue());
-            let mut result = vec![];
-
-            for index in 0..sample_len {
-                let context_size = if index > 0 { 1 } else { tokens.len() };
-                let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-                let input = Tensor::new(ctxt, &self.device)
-                    .unwrap()
-                    .unsqueeze(0)
-                    .expect("create tensor input error");
-                let logits = self.model.forward(&input).unwrap();
-                let logits = logits.squeeze(0).unwrap().to_dtype(self.dtype).unwrap();
-                let logits = if self.repeat_penalty == 1. {
-                    logits
-                } else {
-                    let start_at = tokens.len().saturating_sub(self.repeat_last_n);
-                    candle_transformers::utils::apply_repeat_penalty(
-                        &logits,
-                        self.repeat_penalty,
-                        &tokens[start_at..],
-                    )
-                    .unwrap()
-                };
+        }
 
-                let next_token = self.logits_processor.sample(&logits).unwrap();
-                tokens.push(next_token);
-                generated_tokens += 1;
-                if next_token == eos_token {
-                    break;
-                }
-                let token = self
-                    .tokenizer
-                    .decode(&[next_token], true)
-                    .expect("Token error");
-                if self.verbose_prompt {
-                    println!(
-                        "[Index: {}] [Raw Token: {}] [Decode Token: {}]",
-                        index.blue(),
-                        next_token.green(),
-                        token.yellow()
-                    );
-                }
-                result.push(token);
-                std::io::stdout().flush().unwrap();
+        std::io::stdout().flush().expect("output flush error");
+        let start_gen = std::time::Instant::now();
+        println!("samplelen {}", sample_len.blue());
+
+        // Counted from the per-token callback: `on_token` only sees buffered text chunks, which
+        // can hold several tokens each.
+        let mut generated_tokens = 0usize;
+        let mut on_debug = |index: usize, raw_token: u32, decoded: &str| -> candle::Result<()> {
+            generated_tokens += 1;
+            if verbose_prompt {
+                println!("[Index: {index}] [Raw Token: {raw_token}] [Decode Token: {decoded}]");
             }
-            let dt = start_gen.elapsed();
+            Ok(())
+        };
+        let reply = engine.generate(
+            &line,
+            sample_len,
+            |chunk| {
+                print!("{chunk}");
+                std::io::stdout().flush().unwrap();
+                Ok(true)
+            },
+            Some(&mut on_debug),
+        )?;
+
+        let dt = start_gen.elapsed();
+        println!(
+            "\n{generated_tokens} tokens generated ({:.2} token/s)",
+            generated_tokens as f64 / dt.as_secs_f64(),
+        );
+        if let Some(tool_call) = reply.tool_call {
             println!(
-                "\n{generated_tokens} tokens generated ({:.2} token/s)",
-                generated_tokens as f64 / dt.as_secs_f64(),
+                "\n[tool call] {} {}",
+                tool_call.name.magenta(),
+                tool_call.arguments
             );
-            println!("Result:");
-            for tokens in result {
-                print!("{tokens}");
-            }
         }
-        self.model.reset_kv_cache(); // 清理模型kv
-        Ok(())
+    }
+    Ok(())
+}
+
+/// One line of a `--server` request.
+#[derive(Debug, Deserialize)]
+struct ServerRequest {
+    id: Option<String>,
+    prompt: String,
+    #[serde(default = "default_server_sample_len")]
+    sample_len: usize,
+}
+
+fn default_server_sample_len() -> usize {
+    1000
+}
+
+/// Reads line-delimited JSON requests from stdin and hands each to `worker`, which keeps the
+/// model loaded and processes jobs one at a time. A thread per request streams that request's
+/// tokens back as line-delimited JSON on stdout as soon as the worker produces them, so stdin
+/// isn't blocked waiting for one prompt to finish before the next is queued.
+fn serve(worker: WorkerHandle) {
+    let mut handles = Vec::new();
+    for line in std::io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: ServerRequest = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                let resp = serde_json::json!({"done": true, "error": err.to_string()});
+                println!("{resp}");
+                continue;
+            }
+        };
+        let worker = worker.clone();
+        handles.push(std::thread::spawn(move || {
+            let id = request.id;
+            let events = worker.submit(request.prompt, request.sample_len);
+            for event in events {
+                let resp = match event {
+                    GenerationEvent::Token(token) => {
+                        serde_json::json!({"id": id, "token": token, "done": false})
+                    }
+                    GenerationEvent::Done(reply) => serde_json::json!({
+                        "id": id,
+                        "done": true,
+                        "text": reply.text,
+                        "tool_call": reply.tool_call.map(|t| serde_json::json!({
+                            "name": t.name,
+                            "arguments": t.arguments,
+                        })),
+                    }),
+                    GenerationEvent::Error(err) => {
+                        serde_json::json!({"id": id, "done": true, "error": err})
+                    }
+                };
+                println!("{resp}");
+            }
+        }));
+    }
+    for handle in handles {
+        let _ = handle.join();
     }
 }
 
@@ -156,13 +159,29 @@ struct Args {
     #[arg(long)]
     cpu: bool,
 
-    /// Display the token for the specified prompt.
+    /// Serve line-delimited JSON requests (`{"id": ..., "prompt": ..., "sample_len": ...}`)
+    /// read from stdin, dispatching each to a background worker that keeps the model warm
+    /// across requests. Each token and the final reply are written back as a line-delimited
+    /// JSON response on stdout.
     #[arg(long)]
-    verbose_prompt: bool,
+    server: bool,
 
     #[arg(long)]
     prompt: String,
 
+    /// Display the tokenized prompt and each raw/decoded token as generation proceeds.
+    #[arg(long)]
+    verbose_prompt: bool,
+
+    /// System prompt placed in the `<|system|>` turn of the ChatGLM4 chat template.
+    #[arg(long)]
+    system: Option<String>,
+
+    /// Path to a JSON file holding an array of function declarations (name/description/
+    /// parameters) to expose to the model for function calling.
+    #[arg(long)]
+    tools: Option<String>,
+
     /// The temperature used to generate samples.
     #[arg(long)]
     temperature: Option<f64>,
@@ -171,6 +190,20 @@ struct Args {
     #[arg(long)]
     top_p: Option<f64>,
 
+    /// Only sample among the top-k most likely tokens. Combine with `--top-p` to do
+    /// top-k-then-top-p.
+    #[arg(long)]
+    top_k: Option<usize>,
+
+    /// Discard tokens whose probability is below `min_p` of the most likely token's probability.
+    #[arg(long)]
+    min_p: Option<f64>,
+
+    /// Always take the most likely token (deterministic decoding), ignoring temperature/
+    /// top-k/top-p/min-p.
+    #[arg(long)]
+    greedy: bool,
+
     /// The seed to use when generating random samples.
     #[arg(long)]
     seed: Option<u64>,
@@ -188,6 +221,11 @@ struct Args {
     #[arg(long)]
     weight_file: Option<String>,
 
+    /// GGUF file to load a quantized model from (q4_0/q4_k/q8_0/...), instead of the
+    /// full-precision safetensors weights.
+    #[arg(long)]
+    quantized: Option<String>,
+
     #[arg(long)]
     tokenizer: Option<String>,
 
@@ -246,39 +284,69 @@ p    };
             .get("tokenizer.json")
             .unwrap(),
     };
-    let filenames = match args.weight_file {
-        Some(weight_file) => vec![std::path::PathBuf::from(weight_file)],
-        None => {
-            candle_examples::hub_load_safetensors(&repo, "model.safetensors.index.json").unwrap()
-        }
-    };
     let tokenizer = Tokenizer::from_file(tokenizer_filename).expect("Tokenizer Error");
     let start = std::time::Instant::now();
-    let config = Config::codegeex4();
     let device = candle_examples::device(args.cpu).unwrap();
     let dtype = if device.is_cuda() {
         DType::BF16
     } else {
         DType::F32
     };
-    println!("DType is {:?}", dtype.yellow());
-    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device).unwrap() };
-    let model = Model::new(&config, vb).unwrap();
+
+    let model = match args.quantized {
+        Some(gguf_file) => {
+            println!("loading quantized model from {}", gguf_file.blue());
+            let mut file = std::fs::File::open(&gguf_file).unwrap();
+            let gguf =
+                gguf_file::Content::read(&mut file).expect("failed to read gguf file metadata");
+            let weights = quantized_codegeex4::ModelWeights::from_gguf(gguf, &mut file, &device)
+                .expect("failed to build quantized model from gguf file");
+            Model::Quantized(weights)
+        }
+        None => {
+            let filenames = match args.weight_file {
+                Some(weight_file) => vec![std::path::PathBuf::from(weight_file)],
+                None => candle_examples::hub_load_safetensors(
+                    &repo,
+                    "model.safetensors.index.json",
+                )
+                .unwrap(),
+            };
+            println!("DType is {:?}", dtype.yellow());
+            let config = codegeex4::Config::codegeex4();
+            let vb =
+                unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device).unwrap() };
+            Model::Float(codegeex4::Model::new(&config, vb).unwrap())
+        }
+    };
 
     println!("模型加载完毕 {:?}", start.elapsed().as_secs().green());
 
-    let mut pipeline = TextGeneration::new(
-        model,
-        tokenizer,
+    let tools: Vec<ToolDeclaration> = match args.tools {
+        Some(path) => {
+            let content = std::fs::read_to_string(&path).expect("failed to read tools file");
+            serde_json::from_str(&content).expect("tools file is not a valid JSON array")
+        }
+        None => Vec::new(),
+    };
+
+    let sampling = SamplingOptions {
         seed,
-        args.temperature,
-        args.top_p,
-        args.repeat_penalty,
-        args.repeat_last_n,
-        args.verbose_prompt,
-        &device,
-        dtype,
-    );
-    pipeline.run(args.sample_len)?;
+        temperature: args.temperature,
+        top_p: args.top_p,
+        top_k: args.top_k,
+        min_p: args.min_p,
+        greedy: args.greedy,
+        repeat_penalty: args.repeat_penalty,
+        repeat_last_n: args.repeat_last_n,
+    };
+    let mut engine =
+        Engine::load(model, tokenizer, device, dtype, sampling).with_chat_template(args.system, tools);
+
+    if args.server {
+        serve(WorkerHandle::spawn(engine));
+    } else {
+        run(&mut engine, args.sample_len, args.verbose_prompt).map_err(|_| ())?;
+    }
     Ok(())
 }
@@ -0,0 +1,347 @@
+//! GGUF-quantized counterpart of [`crate::codegeex4::Model`], for running CodeGeeX4-9B
+//! from a community-produced `.gguf` file (q4_0/q4_k/q8_0, ...) instead of full-precision
+//! safetensors.
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
+use candle_transformers::quantized_nn::RmsNorm;
+use candle_transformers::quantized_var_builder::VarBuilder;
+use std::io::{Read, Seek};
+
+fn metadata_usize(md: &std::collections::HashMap<String, gguf_file::Value>, key: &str) -> Result<usize> {
+    match md.get(key) {
+        Some(value) => value
+            .to_u32()
+            .map(|v| v as usize)
+            .map_err(|e| candle_core::Error::Msg(format!("gguf metadata {key} is not an int: {e}"))),
+        None => candle_core::bail!("missing required gguf metadata key `{key}`"),
+    }
+}
+
+fn metadata_f32(md: &std::collections::HashMap<String, gguf_file::Value>, key: &str, default: f32) -> Result<f32> {
+    match md.get(key) {
+        Some(value) => value
+            .to_f32()
+            .map_err(|e| candle_core::Error::Msg(format!("gguf metadata {key} is not a float: {e}"))),
+        None => Ok(default),
+    }
+}
+
+/// Hyperparameters read from a GGUF file's metadata table. Unlike [`crate::codegeex4::Config`],
+/// whose fields are hard-coded for the 9B checkpoint, these are recovered from whatever the
+/// community quantizer wrote, with a clear error for anything required that is missing.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub num_layers: usize,
+    pub hidden_size: usize,
+    pub ffn_hidden_size: usize,
+    pub num_attention_heads: usize,
+    pub num_kv_heads: usize,
+    pub head_dim: usize,
+    pub layernorm_epsilon: f32,
+    pub max_seq_len: usize,
+}
+
+impl Config {
+    fn from_gguf(ct: &gguf_file::Content, arch: &str) -> Result<Self> {
+        let md = &ct.metadata;
+        let key = |name: &str| format!("{arch}.{name}");
+        let num_attention_heads = metadata_usize(md, &key("attention.head_count"))?;
+        let num_kv_heads = match md.get(&key("attention.head_count_kv")) {
+            Some(_) => metadata_usize(md, &key("attention.head_count_kv"))?,
+            None => num_attention_heads,
+        };
+        let hidden_size = metadata_usize(md, &key("embedding_length"))?;
+        Ok(Self {
+            num_layers: metadata_usize(md, &key("block_count"))?,
+            hidden_size,
+            ffn_hidden_size: metadata_usize(md, &key("feed_forward_length"))?,
+            num_attention_heads,
+            num_kv_heads,
+            head_dim: hidden_size / num_attention_heads,
+            layernorm_epsilon: metadata_f32(md, &key("attention.layer_norm_rms_epsilon"), 1e-5)?,
+            max_seq_len: metadata_usize(md, &key("context_length")).unwrap_or(8192),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    dense_h_to_4h: candle_transformers::quantized_nn::Linear,
+    dense_4h_to_h: candle_transformers::quantized_nn::Linear,
+}
+
+impl Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let up_states = self.dense_h_to_4h.forward(xs)?;
+        let gate = up_states.chunk(2, D::Minus1)?;
+        let xs = (gate[0].silu()? * &gate[1])?;
+        self.dense_4h_to_h.forward(&xs)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    cache: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(head_dim: usize, max_seq_len: usize, dev: &Device) -> Result<Self> {
+        let rotary_dim = head_dim / 2;
+        let theta: Vec<_> = (0..rotary_dim)
+            .step_by(2)
+            .map(|i| 1f32 / 10000f64.powf(i as f64 / rotary_dim as f64) as f32)
+            .collect();
+        let theta = Tensor::new(theta.as_slice(), dev)?;
+        let idx_theta = Tensor::arange(0, max_seq_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?
+            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+        let cache = Tensor::stack(&[idx_theta.cos()?, idx_theta.sin()?], D::Minus1)?;
+        Ok(Self { cache })
+    }
+
+    fn apply(&self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (b_sz, num_heads, seq_len, n_embd) = xs.dims4()?;
+        let half = n_embd / 2;
+        let xs_rot = xs.narrow(D::Minus1, 0, half)?;
+        let xs_pass = xs.narrow(D::Minus1, half, half)?;
+        let xs_rot = xs_rot.reshape((b_sz, num_heads, seq_len, half / 2, 2))?;
+        let cache = self.cache.narrow(0, index_pos, seq_len)?;
+        let cos = cache.narrow(D::Minus1, 0, 1)?.broadcast_as(xs_rot.shape())?;
+        let sin = cache.narrow(D::Minus1, 1, 1)?.broadcast_as(xs_rot.shape())?;
+        let x0 = xs_rot.narrow(D::Minus1, 0, 1)?;
+        let x1 = xs_rot.narrow(D::Minus1, 1, 1)?;
+        let rotated = Tensor::cat(
+            &[
+                (&x0 * &cos)?.broadcast_sub(&(&x1 * &sin)?)?,
+                (&x0 * &sin)?.broadcast_add(&(&x1 * &cos)?)?,
+            ],
+            D::Minus1,
+        )?
+        .flatten_from(D::Minus2)?;
+        Tensor::cat(&[rotated, xs_pass], D::Minus1)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SelfAttention {
+    query_key_value: candle_transformers::quantized_nn::Linear,
+    dense: candle_transformers::quantized_nn::Linear,
+    num_attention_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    rotary_embedding: RotaryEmbedding,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl SelfAttention {
+    fn forward(&mut self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+        let qkv = self.query_key_value.forward(xs)?;
+        let q_size = self.num_attention_heads * self.head_dim;
+        let kv_size = self.num_kv_heads * self.head_dim;
+        let q = qkv.narrow(D::Minus1, 0, q_size)?;
+        let k = qkv.narrow(D::Minus1, q_size, kv_size)?;
+        let v = qkv.narrow(D::Minus1, q_size + kv_size, kv_size)?;
+
+        let q = q
+            .reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let q = self.rotary_embedding.apply(&q, index_pos)?.contiguous()?;
+        let k = self.rotary_embedding.apply(&k, index_pos)?.contiguous()?;
+
+        let (k, v) = match &self.kv_cache {
+            None => (k, v),
+            Some((prev_k, prev_v)) => {
+                let k = Tensor::cat(&[prev_k, &k], 2)?;
+                let v = Tensor::cat(&[prev_v, &v], 2)?;
+                (k, v)
+            }
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let repeat = self.num_attention_heads / self.num_kv_heads;
+        let k = candle_transformers::utils::repeat_kv(k, repeat)?;
+        let v = candle_transformers::utils::repeat_kv(v, repeat)?;
+
+        let scale = 1f64 / (self.head_dim as f64).sqrt();
+        let attn_weights = (q.matmul(&k.transpose(D::Minus2, D::Minus1)?)? * scale)?;
+        let attn_weights = if seq_len <= 1 {
+            attn_weights
+        } else {
+            let mask = Tensor::triu2(seq_len, DType::U8, xs.device())?.broadcast_as((
+                b_sz,
+                self.num_attention_heads,
+                seq_len,
+                seq_len,
+            ))?;
+            let neg_inf = Tensor::new(f32::NEG_INFINITY, xs.device())?.broadcast_as(mask.shape())?;
+            mask.where_cond(&neg_inf.to_dtype(attn_weights.dtype())?, &attn_weights)?
+        };
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        let attn_output = attn_weights.matmul(&v)?;
+        let attn_output = attn_output
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, self.num_attention_heads * self.head_dim))?;
+        self.dense.forward(&attn_output)
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.kv_cache = None
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    input_layernorm: RmsNorm,
+    self_attention: SelfAttention,
+    post_attention_layernorm: RmsNorm,
+    mlp: Mlp,
+}
+
+impl Block {
+    fn forward(&mut self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let layernorm_output = self.input_layernorm.forward(xs)?;
+        let attention_output = self.self_attention.forward(&layernorm_output, index_pos)?;
+        let layernorm_input = (xs + attention_output)?;
+        let layernorm_output = self.post_attention_layernorm.forward(&layernorm_input)?;
+        let mlp_output = self.mlp.forward(&layernorm_output)?;
+        layernorm_input + mlp_output
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.self_attention.reset_kv_cache()
+    }
+}
+
+fn qtensor_to_tensor(vb: &VarBuilder, name: &str) -> Result<Tensor> {
+    vb.get_no_shape(name)?.dequantize(&Device::Cpu)
+}
+
+/// The GGUF-quantized counterpart of [`crate::codegeex4::Model`]. The linear-layer weights stay
+/// quantized (q4_0/q4_k/q8_0/...) in memory and run through candle's quantized matmul, trading a
+/// little speed for running the 9B model on far less memory than full BF16/F32. The token
+/// embedding table and the qkv biases are dequantized once, when the model is loaded.
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    tok_embeddings: Tensor,
+    layers: Vec<Block>,
+    final_layernorm: RmsNorm,
+    output_layer: candle_transformers::quantized_nn::Linear,
+    device: Device,
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: Read + Seek>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let arch = ct
+            .metadata
+            .get("general.architecture")
+            .and_then(|v| v.to_string().ok())
+            .cloned()
+            .unwrap_or_else(|| "chatglm".to_string());
+        let cfg = Config::from_gguf(&ct, &arch)?;
+        let vb = VarBuilder::from_gguf_buffer(&ct.tensor_infos, reader, device)?;
+
+        let tok_embeddings = qtensor_to_tensor(&vb, "token_embd.weight")?;
+        let rotary_embedding = RotaryEmbedding::new(cfg.head_dim, cfg.max_seq_len, device)?;
+
+        let mut layers = Vec::with_capacity(cfg.num_layers);
+        for layer_idx in 0..cfg.num_layers {
+            let prefix = format!("blk.{layer_idx}");
+            let self_attention = SelfAttention {
+                query_key_value: candle_transformers::quantized_nn::Linear::new(
+                    vb.get_no_shape(&format!("{prefix}.attn_qkv.weight"))?,
+                    Some(vb.get_no_shape(&format!("{prefix}.attn_qkv.bias"))?.dequantize(device)?),
+                ),
+                dense: candle_transformers::quantized_nn::Linear::new(
+                    vb.get_no_shape(&format!("{prefix}.attn_output.weight"))?,
+                    None,
+                ),
+                num_attention_heads: cfg.num_attention_heads,
+                num_kv_heads: cfg.num_kv_heads,
+                head_dim: cfg.head_dim,
+                rotary_embedding: rotary_embedding.clone(),
+                kv_cache: None,
+            };
+            let mlp = Mlp {
+                dense_h_to_4h: candle_transformers::quantized_nn::Linear::new(
+                    vb.get_no_shape(&format!("{prefix}.ffn_up.weight"))?,
+                    None,
+                ),
+                dense_4h_to_h: candle_transformers::quantized_nn::Linear::new(
+                    vb.get_no_shape(&format!("{prefix}.ffn_down.weight"))?,
+                    None,
+                ),
+            };
+            let block = Block {
+                input_layernorm: RmsNorm::from_qtensor(
+                    vb.get_no_shape(&format!("{prefix}.attn_norm.weight"))?,
+                    cfg.layernorm_epsilon as f64,
+                )?,
+                self_attention,
+                post_attention_layernorm: RmsNorm::from_qtensor(
+                    vb.get_no_shape(&format!("{prefix}.ffn_norm.weight"))?,
+                    cfg.layernorm_epsilon as f64,
+                )?,
+                mlp,
+            };
+            layers.push(block);
+        }
+        let final_layernorm = RmsNorm::from_qtensor(
+            vb.get_no_shape("output_norm.weight")?,
+            cfg.layernorm_epsilon as f64,
+        )?;
+        let output_layer =
+            candle_transformers::quantized_nn::Linear::new(vb.get_no_shape("output.weight")?, None);
+
+        Ok(Self {
+            tok_embeddings,
+            layers,
+            final_layernorm,
+            output_layer,
+            device: device.clone(),
+        })
+    }
+
+    pub fn forward(&mut self, xs: &Tensor) -> Result<Tensor> {
+        let (_b_size, seq_len) = xs.dims2()?;
+        let index_pos = self.layers[0]
+            .self_attention
+            .kv_cache
+            .as_ref()
+            .map_or(0, |(k, _)| k.dim(2).unwrap_or(0));
+        let mut xs = self.tok_embeddings.index_select(&xs.flatten_all()?, 0)?.reshape((
+            xs.dim(0)?,
+            seq_len,
+            self.tok_embeddings.dim(1)?,
+        ))?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, index_pos)?;
+        }
+        let xs = self.final_layernorm.forward(&xs)?;
+        let xs = xs.i((.., seq_len - 1, ..))?;
+        self.output_layer.forward(&xs)
+    }
+
+    pub fn reset_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.reset_kv_cache()
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+}
@@ -0,0 +1,138 @@
+//! Adapted from candle-examples: decode generated tokens incrementally without corrupting
+//! multi-byte UTF-8 sequences or BPE pieces that span several tokens.
+use candle_core::Result;
+
+/// Buffers generated token ids and only emits the newly-decoded suffix once it is known to be
+/// a complete, valid UTF-8 chunk. Decoding token-by-token with `tokenizer.decode` on its own can
+/// split a multi-byte character (e.g. Chinese output) or a BPE piece across two tokens, which
+/// corrupts the output; re-decoding a growing window and diffing against the previous decode
+/// avoids that.
+pub struct TokenOutputStream {
+    tokenizer: tokenizers::Tokenizer,
+    tokens: Vec<u32>,
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl TokenOutputStream {
+    pub fn new(tokenizer: tokenizers::Tokenizer) -> Self {
+        Self {
+            tokenizer,
+            tokens: Vec::new(),
+            prev_index: 0,
+            current_index: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> tokenizers::Tokenizer {
+        self.tokenizer
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        match self.tokenizer.decode(tokens, true) {
+            Ok(str) => Ok(str),
+            Err(err) => candle_core::bail!("cannot decode: {err}"),
+        }
+    }
+
+    /// Feeds a newly-generated token id into the stream. Returns the text that became available
+    /// as a result, if any. A chunk is only emitted once decoding the token window that includes
+    /// it grew and its last character is alphanumeric rather than the Unicode replacement
+    /// character, i.e. once it is no longer at risk of being half of a multi-byte sequence or a
+    /// BPE piece that spans more than one token.
+    pub fn next_token(&mut self, token: u32) -> Result<Option<String>> {
+        let prev_text = if self.tokens.is_empty() {
+            String::new()
+        } else {
+            let tokens = &self.tokens[self.prev_index..self.current_index];
+            self.decode(tokens)?
+        };
+        self.tokens.push(token);
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if text.len() > prev_text.len() && text.chars().last().unwrap().is_alphanumeric() {
+            let text = text.split_at(prev_text.len());
+            self.prev_index = self.current_index;
+            self.current_index = self.tokens.len();
+            Ok(Some(text.1.to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Decodes and returns whatever text is still buffered once generation has finished.
+    pub fn decode_rest(&self) -> Result<Option<String>> {
+        let prev_text = if self.tokens.is_empty() {
+            String::new()
+        } else {
+            let tokens = &self.tokens[self.prev_index..self.current_index];
+            self.decode(tokens)?
+        };
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if text.len() > prev_text.len() {
+            let text = text.split_at(prev_text.len());
+            Ok(Some(text.1.to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn decode_all(&self) -> Result<String> {
+        self.decode(&self.tokens)
+    }
+
+    pub fn get_token(&self, token_s: &str) -> Option<u32> {
+        self.tokenizer.get_vocab(true).get(token_s).copied()
+    }
+
+    pub fn tokenizer(&self) -> &tokenizers::Tokenizer {
+        &self.tokenizer
+    }
+
+    pub fn clear(&mut self) {
+        self.tokens.clear();
+        self.prev_index = 0;
+        self.current_index = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::Tokenizer;
+
+    fn test_tokenizer() -> Tokenizer {
+        let vocab: HashMap<String, u32> = [
+            ("The".to_string(), 0),
+            ("quick".to_string(), 1),
+            ("fox".to_string(), 2),
+            ("<unk>".to_string(), 3),
+        ]
+        .into_iter()
+        .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .unwrap();
+        Tokenizer::new(model)
+    }
+
+    #[test]
+    fn next_token_emits_text_as_soon_as_each_token_decodes_cleanly() {
+        let mut stream = TokenOutputStream::new(test_tokenizer());
+        let mut chunks = Vec::new();
+        for token in [0u32, 1, 2] {
+            if let Some(chunk) = stream.next_token(token).unwrap() {
+                chunks.push(chunk);
+            }
+        }
+        // Every token here decodes to a complete word on its own, so each `next_token` call
+        // should hand back its chunk right away instead of withholding everything until
+        // `decode_rest` is finally called.
+        assert_eq!(chunks, vec!["The", " quick", " fox"]);
+        assert_eq!(chunks.concat(), stream.decode_all().unwrap());
+        assert!(stream.decode_rest().unwrap().is_none());
+    }
+}
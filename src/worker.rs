@@ -0,0 +1,72 @@
+//! Keeps a loaded [`Engine`](crate::engine::Engine) warm on a background thread so many
+//! prompts can be served without paying the model-load cost more than once. Jobs are processed
+//! one at a time, in submission order, with the KV cache reset between them; each job streams
+//! its tokens back over its own channel so callers don't block on each other.
+use std::sync::mpsc;
+
+use crate::chat_template::AssistantReply;
+use crate::engine::Engine;
+
+/// One decoded chunk or terminal event for a submitted generation.
+pub enum GenerationEvent {
+    Token(String),
+    Done(AssistantReply),
+    Error(String),
+}
+
+struct Job {
+    prompt: String,
+    sample_len: usize,
+    events: mpsc::Sender<GenerationEvent>,
+}
+
+/// A handle to the background worker thread. Cloning it is cheap (it just clones the channel
+/// sender), so it can be shared across however many request handlers submit work concurrently.
+#[derive(Clone)]
+pub struct WorkerHandle {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl WorkerHandle {
+    /// Spawns the worker thread, moving `engine` onto it. The thread runs until every
+    /// `WorkerHandle` (and thus the job sender) has been dropped.
+    pub fn spawn(mut engine: Engine) -> Self {
+        let (jobs, jobs_rx) = mpsc::channel::<Job>();
+        std::thread::spawn(move || {
+            for job in jobs_rx {
+                let result = engine.generate(
+                    &job.prompt,
+                    job.sample_len,
+                    |chunk| {
+                        let _ = job.events.send(GenerationEvent::Token(chunk.to_string()));
+                        Ok(true)
+                    },
+                    None,
+                );
+                let event = match result {
+                    Ok(reply) => GenerationEvent::Done(reply),
+                    Err(err) => GenerationEvent::Error(err.to_string()),
+                };
+                let _ = job.events.send(event);
+                engine.reset_kv_cache();
+            }
+        });
+        Self { jobs }
+    }
+
+    /// Queues a prompt for generation and returns a receiver that streams its tokens, followed
+    /// by a final `Done` or `Error` event.
+    pub fn submit(&self, prompt: String, sample_len: usize) -> mpsc::Receiver<GenerationEvent> {
+        let (events, events_rx) = mpsc::channel();
+        // The worker thread only goes away if every `WorkerHandle` has already been dropped, in
+        // which case there is nobody left to submit a job in the first place.
+        self.jobs
+            .send(Job {
+                prompt,
+                sample_len,
+                events,
+            })
+            .expect("worker thread terminated unexpectedly");
+        events_rx
+    }
+}